@@ -4,7 +4,9 @@ use std::{
     hint::black_box,
     io::{BufReader, Read},
 };
-use templating_engine::{parse, parse_simd};
+use templating_engine::parse;
+#[cfg(feature = "simd")]
+use templating_engine::parse_simd;
 
 fn criterion_benchmark(c: &mut Criterion) {
     let mut f = BufReader::new(File::open("./test.txt").unwrap());
@@ -13,6 +15,7 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     let mut g = c.benchmark_group("Parse Template");
     g.throughput(criterion::Throughput::Bytes(s.len() as u64));
+    #[cfg(feature = "simd")]
     g.bench_function("newlines_simd", |b| {
         b.iter(|| parse_simd::parse_template(black_box(s.as_bytes())))
     });
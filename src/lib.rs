@@ -1,8 +1,8 @@
-#![feature(portable_simd)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
 
 use std::io::Write;
 
-use parse::{parse_template, Block, NumberedBlock};
+use parse::{parse_template_with, Block, NumberedBlock, Special, Syntax};
 
 pub mod parse {
     use nom::{
@@ -25,7 +25,7 @@ pub mod parse {
             self.i.input_len()
         }
     }
-    fn make_special_parser<'a>(
+    pub(crate) fn make_special_parser<'a>(
         left_sep: &'static [u8],
         right_sep: &'static [u8],
         constructor: fn(&'a [u8]) -> Special<'a>,
@@ -65,14 +65,67 @@ pub mod parse {
         }
     }
 
+    /// The tag pairs a template is parsed with: `(left, right)` for each
+    /// of the three kinds of tag.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Syntax {
+        pub percent: (&'static [u8], &'static [u8]),
+        pub curly: (&'static [u8], &'static [u8]),
+        pub hash: (&'static [u8], &'static [u8]),
+    }
+
+    impl Default for Syntax {
+        /// `{% %}`, `{{ }}`, `{# #}` — the engine's built-in delimiters.
+        fn default() -> Self {
+            Self {
+                percent: (b"{%", b"%}"),
+                curly: (b"{{", b"}}"),
+                hash: (b"{#", b"#}"),
+            }
+        }
+    }
+
+    impl Syntax {
+        fn tags(&self) -> [&'static [u8]; 6] {
+            [
+                self.percent.0,
+                self.percent.1,
+                self.curly.0,
+                self.curly.1,
+                self.hash.0,
+                self.hash.1,
+            ]
+        }
+
+        /// Whether `input` starts with one of this syntax's *opening*
+        /// delimiters (`{%`, `{{` or `{#` by default).
+        pub(crate) fn opens_with(&self, input: &[u8]) -> bool {
+            [self.percent.0, self.curly.0, self.hash.0]
+                .iter()
+                .any(|open| input.starts_with(open))
+        }
+    }
+
+    /// Parse a template using the engine's built-in `{% %}`/`{{ }}`/`{# #}`
+    /// delimiters. Shorthand for [`parse_template_with`] with
+    /// `Syntax::default()`.
     pub fn parse_template<'a>(
         input: &'a [u8],
     ) -> IResult<NumberedInput<'a>, Vec<NumberedBlock<'a>>> {
-        let percent = make_special_parser(b"{%", b"%}", Special::TagPercent);
-        let curly = make_special_parser(b"{{", b"}}", Special::TagCurly);
-        let hash = make_special_parser(b"{#", b"#}", Special::TagHash);
+        parse_template_with(input, &Syntax::default())
+    }
 
-        let plain_parser = plain.map(|x| NumberedBlock {
+    /// Parse a template using a custom [`Syntax`], e.g. to support `<% %>`
+    /// or `[[ ]]` style tags instead of the built-in delimiters.
+    pub fn parse_template_with<'a>(
+        input: &'a [u8],
+        syntax: &Syntax,
+    ) -> IResult<NumberedInput<'a>, Vec<NumberedBlock<'a>>> {
+        let percent = make_special_parser(syntax.percent.0, syntax.percent.1, Special::TagPercent);
+        let curly = make_special_parser(syntax.curly.0, syntax.curly.1, Special::TagCurly);
+        let hash = make_special_parser(syntax.hash.0, syntax.hash.1, Special::TagHash);
+
+        let plain_parser = (|ni| plain(syntax, ni)).map(|x| NumberedBlock {
             line_number: x.line_number,
             block: Block::Plain(x.i),
         });
@@ -116,23 +169,30 @@ pub mod parse {
             .parse(input)
     }
 
-    /// match any separator
-    fn any_separator(input: &[u8]) -> IResult<&[u8], &[u8]> {
-        alt((
-            tag("{{"),
-            tag("{%"),
-            tag("{#"),
-            tag("}}"),
-            tag("%}"),
-            tag("#}"),
-        ))(input)
+    /// Match any of `syntax`'s configured tags, left or right.
+    fn any_separator_with<'a>(syntax: &Syntax, input: &'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
+        for t in syntax.tags() {
+            if let Ok(ok) = tag::<_, _, Error<&[u8]>>(t)(input) {
+                return Ok(ok);
+            }
+        }
+        Err(nom::Err::Error(Error::new(input, ErrorKind::Tag)))
     }
 
-    /// parse plain text until any separator occurs
-    fn plain(input: NumberedInput<'_>) -> IResult<NumberedInput<'_>, NumberedInput<'_>> {
+    /// match any of the engine's built-in separators
+    #[cfg(feature = "simd")]
+    pub(crate) fn any_separator(input: &[u8]) -> IResult<&[u8], &[u8]> {
+        any_separator_with(&Syntax::default(), input)
+    }
+
+    /// parse plain text until any of `syntax`'s separators occurs
+    fn plain<'a>(
+        syntax: &Syntax,
+        input: NumberedInput<'a>,
+    ) -> IResult<NumberedInput<'a>, NumberedInput<'a>> {
         let mut line_number = input.line_number;
         for i in 0..input.i.len() {
-            if any_separator(&input.i[i..]).is_ok() {
+            if any_separator_with(syntax, &input.i[i..]).is_ok() {
                 // must not parse any separators as part of raw output
                 if i == 0 {
                     return Err(nom::Err::Failure(nom::error::Error::new(
@@ -156,7 +216,7 @@ pub mod parse {
                 line_number += 1;
             }
         }
-        if input.i.len() == 0 {
+        if input.i.is_empty() {
             return Err(nom::Err::Error(Error::new(input, ErrorKind::Fail)));
         }
         let (rest, parsed) = input.i.take_split(input.i.len());
@@ -253,47 +313,1281 @@ pub mod parse {
 }
 
 #[cfg(feature = "simd")]
-mod parse_simd {}
+pub mod parse_simd {
+    //! A SIMD-accelerated drop-in replacement for [`crate::parse`]'s plain
+    //! text scanner. Output is byte-identical to
+    //! [`parse::parse_template`](crate::parse::parse_template); only the
+    //! search for the next delimiter is different.
+
+    use std::simd::{cmp::SimdPartialEq, Simd};
+
+    use nom::{
+        branch::alt,
+        error::{Error, ErrorKind},
+        multi::many0,
+        IResult, InputTake, Parser,
+    };
+
+    use crate::parse::{any_separator, make_special_parser, NumberedInput};
+    use crate::{Block, NumberedBlock, Special};
+
+    const LANES: usize = 32;
+
+    /// Scan `input` for the next byte that could start a delimiter,
+    /// verifying each candidate with the same scalar check the complete
+    /// parser uses, and counting newlines along the way.
+    ///
+    /// Returns the offset of the first real delimiter (or `input.len()`
+    /// if there is none) and the number of `\n`s seen before it.
+    fn scan(input: &[u8]) -> (usize, usize) {
+        let open_curly = Simd::<u8, LANES>::splat(b'{');
+        let close_curly = Simd::<u8, LANES>::splat(b'}');
+        let percent = Simd::<u8, LANES>::splat(b'%');
+        let hash = Simd::<u8, LANES>::splat(b'#');
+        let newline = Simd::<u8, LANES>::splat(b'\n');
+
+        let mut i = 0;
+        let mut newlines = 0;
+        while i + LANES <= input.len() {
+            let chunk = Simd::<u8, LANES>::from_slice(&input[i..i + LANES]);
+            let candidate_mask = chunk.simd_eq(open_curly)
+                | chunk.simd_eq(close_curly)
+                | chunk.simd_eq(percent)
+                | chunk.simd_eq(hash);
+            let newline_mask = chunk.simd_eq(newline);
+            let candidate_bits = candidate_mask.to_bitmask();
+
+            if candidate_bits != 0 {
+                let mut remaining = candidate_bits;
+                while remaining != 0 {
+                    let bit = remaining.trailing_zeros() as usize;
+                    let offset = i + bit;
+                    // A delimiter that straddles this lane's boundary is
+                    // still caught here: `any_separator` looks at the
+                    // full remainder of `input`, not just this lane.
+                    if any_separator(&input[offset..]).is_ok() {
+                        let before_candidate = if bit == 0 { 0 } else { (1u64 << bit) - 1 };
+                        newlines += (newline_mask.to_bitmask() & before_candidate).count_ones()
+                            as usize;
+                        return (offset, newlines);
+                    }
+                    remaining &= remaining - 1;
+                }
+            }
+            newlines += newline_mask.to_bitmask().count_ones() as usize;
+            i += LANES;
+        }
+
+        // Tail shorter than one SIMD vector: fall back to the scalar scan.
+        while i < input.len() {
+            if any_separator(&input[i..]).is_ok() {
+                return (i, newlines);
+            }
+            if input[i] == b'\n' {
+                newlines += 1;
+            }
+            i += 1;
+        }
+        (input.len(), newlines)
+    }
+
+    /// SIMD-accelerated equivalent of [`crate::parse`]'s private `plain`
+    /// parser.
+    fn plain(input: NumberedInput<'_>) -> IResult<NumberedInput<'_>, NumberedInput<'_>> {
+        let (offset, newlines) = scan(input.i);
+        if offset == 0 {
+            return Err(if input.i.is_empty() {
+                nom::Err::Error(Error::new(input, ErrorKind::Fail))
+            } else {
+                // must not parse a separator as part of raw output
+                nom::Err::Failure(Error::new(input, ErrorKind::Tag))
+            });
+        }
+        let (rest, parsed) = input.i.take_split(offset);
+        Ok((
+            NumberedInput {
+                line_number: input.line_number + newlines,
+                i: rest,
+            },
+            NumberedInput {
+                line_number: input.line_number,
+                i: parsed,
+            },
+        ))
+    }
+
+    pub fn parse_template(input: &[u8]) -> IResult<NumberedInput<'_>, Vec<NumberedBlock<'_>>> {
+        let percent = make_special_parser(b"{%", b"%}", Special::TagPercent);
+        let curly = make_special_parser(b"{{", b"}}", Special::TagCurly);
+        let hash = make_special_parser(b"{#", b"#}", Special::TagHash);
+
+        let plain_parser = plain.map(|x| NumberedBlock {
+            line_number: x.line_number,
+            block: Block::Plain(x.i),
+        });
+
+        let block_parser = alt((percent, curly, hash, plain_parser));
+
+        many0(block_parser)(NumberedInput {
+            line_number: 0,
+            i: input,
+        })
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::parse_template;
+        use crate::parse::parse_template as parse_template_scalar;
+
+        fn assert_matches_scalar(input: &[u8]) {
+            let (simd_rest, simd_blocks) = parse_template(input).unwrap();
+            let (scalar_rest, scalar_blocks) = parse_template_scalar(input).unwrap();
+            assert_eq!(format!("{simd_blocks:?}"), format!("{scalar_blocks:?}"));
+            assert_eq!(simd_rest.i, scalar_rest.i);
+            assert_eq!(simd_rest.line_number, scalar_rest.line_number);
+        }
+
+        #[test]
+        fn matches_scalar_parser() {
+            assert_matches_scalar(b"Hello {{ world }}");
+            assert_matches_scalar(b"{% for x in xs %}\n{{ x }}\n{% endfor %}");
+            assert_matches_scalar(b"{# a comment #}plain text with no tags at all");
+            // exercise the lane-boundary straddle: pad so the delimiter
+            // falls exactly across a 32-byte chunk edge.
+            let mut padded = vec![b'x'; 31];
+            padded.extend_from_slice(b"{{ y }}");
+            assert_matches_scalar(&padded);
+        }
+    }
+}
+
+pub mod streaming {
+    //! Incremental parsing for input that arrives in chunks (a large file,
+    //! a socket, ...), for callers who can't hold the whole template in
+    //! one `&[u8]` up front the way [`parse::parse_template`] requires.
+    //!
+    //! Blocks can't borrow from the streaming buffer the way
+    //! [`parse::NumberedBlock`] borrows from its input slice: [`feed`]
+    //! drains completed bytes out of the buffer as it goes, so a
+    //! completed block is handed back as an owned [`OwnedNumberedBlock`]
+    //! instead.
+    //!
+    //! [`feed`]: StreamingParser::feed
+
+    use nom::{bytes::streaming::{tag, take_until}, sequence::tuple, IResult, Parser};
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum OwnedSpecial {
+        TagPercent(Vec<u8>), // {% %}
+        TagCurly(Vec<u8>),   // {{ }}
+        TagHash(Vec<u8>),    // {# #}
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum OwnedBlock {
+        Special(OwnedSpecial),
+        Plain(Vec<u8>),
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct OwnedNumberedBlock {
+        pub line_number: usize,
+        pub block: OwnedBlock,
+    }
+
+    /// A tag was opened but the input ended before it was ever closed, or
+    /// a closing delimiter appeared with no tag open.
+    #[derive(Clone, Copy, Debug)]
+    pub struct StreamError {
+        pub line_number: usize,
+    }
+
+    const SEPARATORS: [&[u8]; 6] = [b"{{", b"{%", b"{#", b"}}", b"%}", b"#}"];
+
+    fn starts_with_any_separator(input: &[u8]) -> bool {
+        SEPARATORS.iter().any(|sep| input.starts_with(sep))
+    }
+
+    fn is_delimiter_start_byte(b: u8) -> bool {
+        matches!(b, b'{' | b'}' | b'%' | b'#')
+    }
+
+    fn parse_special<'a>(
+        left_sep: &'static [u8],
+        right_sep: &'static [u8],
+        input: &'a [u8],
+    ) -> IResult<&'a [u8], &'a [u8]> {
+        tuple((tag(left_sep), take_until(right_sep), tag(right_sep)))
+            .map(|(_l, m, _r)| m)
+            .parse(input)
+    }
+
+    /// Try to take one block off the front of `buf`.
+    ///
+    /// Returns the number of bytes consumed, the newlines within it, and
+    /// the block itself; `None` if `buf` doesn't yet contain enough to
+    /// know where the next block ends.
+    fn try_parse_one(
+        buf: &[u8],
+        line_number: usize,
+    ) -> Result<Option<(usize, usize, OwnedNumberedBlock)>, StreamError> {
+        for (left, right, ctor) in [
+            (&b"{%"[..], &b"%}"[..], OwnedSpecial::TagPercent as fn(Vec<u8>) -> OwnedSpecial),
+            (&b"{{"[..], &b"}}"[..], OwnedSpecial::TagCurly as fn(Vec<u8>) -> OwnedSpecial),
+            (&b"{#"[..], &b"#}"[..], OwnedSpecial::TagHash as fn(Vec<u8>) -> OwnedSpecial),
+        ] {
+            if buf.starts_with(left) {
+                return match parse_special(left, right, buf) {
+                    Ok((rest, body)) => {
+                        let consumed = buf.len() - rest.len();
+                        let newlines = body.iter().filter(|&&b| b == b'\n').count();
+                        Ok(Some((
+                            consumed,
+                            newlines,
+                            OwnedNumberedBlock {
+                                line_number,
+                                block: OwnedBlock::Special(ctor(body.trim_ascii().to_vec())),
+                            },
+                        )))
+                    }
+                    Err(nom::Err::Incomplete(_)) => Ok(None),
+                    Err(_) => Err(StreamError { line_number }),
+                };
+            }
+        }
+
+        let mut newlines = 0;
+        for i in 0..buf.len() {
+            if starts_with_any_separator(&buf[i..]) {
+                if i == 0 {
+                    // a separator belonging to a different tag kind must
+                    // not be swallowed as plain text
+                    return Err(StreamError { line_number });
+                }
+                return Ok(Some(plain_block(buf, i, newlines, line_number)));
+            }
+            // a lone delimiter-starting byte at the very end of `buf`
+            // might combine with the start of the next chunk, so we
+            // can't tell yet whether it begins a separator
+            if i == buf.len() - 1 && is_delimiter_start_byte(buf[i]) {
+                return if i == 0 {
+                    Ok(None)
+                } else {
+                    Ok(Some(plain_block(buf, i, newlines, line_number)))
+                };
+            }
+            if buf[i] == b'\n' {
+                newlines += 1;
+            }
+        }
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(plain_block(buf, buf.len(), newlines, line_number)))
+        }
+    }
+
+    fn plain_block(
+        buf: &[u8],
+        len: usize,
+        newlines: usize,
+        line_number: usize,
+    ) -> (usize, usize, OwnedNumberedBlock) {
+        (
+            len,
+            newlines,
+            OwnedNumberedBlock {
+                line_number,
+                block: OwnedBlock::Plain(buf[..len].to_vec()),
+            },
+        )
+    }
+
+    /// Feeds a template to the parser one chunk at a time, carrying the
+    /// unconsumed tail and the cumulative `line_number` across calls.
+    #[derive(Clone, Debug, Default)]
+    pub struct StreamingParser {
+        buffer: Vec<u8>,
+        line_number: usize,
+    }
+
+    impl StreamingParser {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Feed in the next chunk, returning every block that could be
+        /// completed with the bytes seen so far.
+        pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<OwnedNumberedBlock>, StreamError> {
+            self.buffer.extend_from_slice(chunk);
+            let mut out = Vec::new();
+            while let Some((consumed, newlines, block)) =
+                try_parse_one(&self.buffer, self.line_number)?
+            {
+                self.line_number += newlines;
+                self.buffer.drain(..consumed);
+                out.push(block);
+            }
+            Ok(out)
+        }
+
+        /// Signal that no more input is coming, flushing any trailing
+        /// plain text and erroring if a tag was left open.
+        pub fn finish(mut self) -> Result<Vec<OwnedNumberedBlock>, StreamError> {
+            if self.buffer.is_empty() {
+                return Ok(Vec::new());
+            }
+            if SEPARATORS[..3]
+                .iter()
+                .any(|open| self.buffer.starts_with(open))
+            {
+                return Err(StreamError {
+                    line_number: self.line_number,
+                });
+            }
+            Ok(vec![OwnedNumberedBlock {
+                line_number: self.line_number,
+                block: OwnedBlock::Plain(std::mem::take(&mut self.buffer)),
+            }])
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn feeds_a_tag_split_across_chunks() {
+            let mut p = StreamingParser::new();
+            assert_eq!(p.feed(b"Hello {{ wor").unwrap(), vec![OwnedNumberedBlock {
+                line_number: 0,
+                block: OwnedBlock::Plain(b"Hello ".to_vec()),
+            }]);
+            assert_eq!(
+                p.feed(b"ld }}!").unwrap(),
+                vec![
+                    OwnedNumberedBlock {
+                        line_number: 0,
+                        block: OwnedBlock::Special(OwnedSpecial::TagCurly(b"world".to_vec())),
+                    },
+                    OwnedNumberedBlock {
+                        line_number: 0,
+                        block: OwnedBlock::Plain(b"!".to_vec()),
+                    },
+                ]
+            );
+            assert_eq!(p.finish().unwrap(), Vec::new());
+        }
+
+        #[test]
+        fn holds_back_an_ambiguous_delimiter_prefix() {
+            let mut p = StreamingParser::new();
+            // the trailing "{" could become "{{", "{%" or "{#"
+            assert_eq!(
+                p.feed(b"a{").unwrap(),
+                vec![OwnedNumberedBlock {
+                    line_number: 0,
+                    block: OwnedBlock::Plain(b"a".to_vec()),
+                }]
+            );
+            // it was just a stray brace all along
+            assert_eq!(
+                p.feed(b"b").unwrap(),
+                vec![OwnedNumberedBlock {
+                    line_number: 0,
+                    block: OwnedBlock::Plain(b"{b".to_vec()),
+                }]
+            );
+        }
+
+        #[test]
+        fn errors_on_unterminated_tag_at_end_of_input() {
+            let mut p = StreamingParser::new();
+            p.feed(b"{{ unterminated").unwrap();
+            assert!(p.finish().is_err());
+        }
+    }
+}
+
+pub mod handler {
+    //! Pluggable output rendering, kept separate from the parser itself.
+    //!
+    //! Modeled after orgize's `Render`/`HtmlHandler` split: a [`Handler`] is
+    //! threaded through [`ParsedTemplate::instantiate`] alongside the
+    //! writer, so callers can plug in their own rendering logic for each
+    //! kind of tag without touching the parser.
+
+    use std::io::{self, Write};
+
+    /// Called by `instantiate` as it walks the template, and decides how
+    /// (or whether) to render each tag. `{% for %}`/`{% if %}` and their
+    /// closers are handled by `instantiate` itself and never reach it.
+    pub trait Handler {
+        /// `{{ path }}`: `name` is the rendered value `path` resolved to in
+        /// the context, not the tag's inner bytes. Not called when the path
+        /// does not resolve.
+        fn expr(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()>;
+        /// `{% name %}`
+        fn statement(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()>;
+        /// `{# name #}`
+        fn comment(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()>;
+    }
+
+    /// Writes what it is handed back out verbatim: the resolved value for
+    /// `{{ }}`, and the trimmed inner bytes for `{% %}` and `{# #}`.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct EchoHandler;
+
+    impl Handler for EchoHandler {
+        fn expr(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            wr.write_all(name)
+        }
+
+        fn statement(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            wr.write_all(name)
+        }
+
+        fn comment(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            wr.write_all(name)
+        }
+    }
+
+    /// Like [`EchoHandler`], but HTML-escapes `{{ }}` output so templates
+    /// can safely echo untrusted text into an HTML document.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct EscapeHtmlHandler;
+
+    impl Handler for EscapeHtmlHandler {
+        fn expr(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            write_html_escaped(name, wr)
+        }
+
+        fn statement(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            wr.write_all(name)
+        }
+
+        fn comment(&mut self, name: &[u8], wr: &mut impl Write) -> io::Result<()> {
+            wr.write_all(name)
+        }
+    }
+
+    fn write_html_escaped(bytes: &[u8], wr: &mut impl Write) -> io::Result<()> {
+        let mut start = 0;
+        for (i, &b) in bytes.iter().enumerate() {
+            let escaped: &[u8] = match b {
+                b'&' => b"&amp;",
+                b'<' => b"&lt;",
+                b'>' => b"&gt;",
+                b'"' => b"&quot;",
+                b'\'' => b"&#39;",
+                _ => continue,
+            };
+            wr.write_all(&bytes[start..i])?;
+            wr.write_all(escaped)?;
+            start = i + 1;
+        }
+        wr.write_all(&bytes[start..])
+    }
+}
+
+pub mod context {
+    //! The runtime data a template is instantiated against.
+
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
+    /// A value bound in a [`Context`].
+    ///
+    /// Deliberately a small closed set of JSON-ish shapes rather than an
+    /// open trait: `instantiate` only ever needs to read these back out
+    /// and render them, never to call back into user code.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Value {
+        Str(Vec<u8>),
+        Num(f64),
+        Bool(bool),
+        List(Vec<Value>),
+        /// A nested object, looked up by a path segment (`user.name`).
+        Map(HashMap<Vec<u8>, Value>),
+    }
+
+    impl Value {
+        /// Render this value the way it should appear in `{{ }}` output.
+        ///
+        /// Borrows the underlying bytes for `Str` so that, in the common
+        /// case, writing a looked-up value costs no allocation.
+        pub fn render(&self) -> Cow<'_, [u8]> {
+            match self {
+                Value::Str(s) => Cow::Borrowed(s),
+                Value::Num(n) => Cow::Owned(format!("{n}").into_bytes()),
+                Value::Bool(b) => Cow::Borrowed(if *b { b"true" } else { b"false" }),
+                Value::List(items) => {
+                    let mut out = Vec::new();
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.extend_from_slice(b", ");
+                        }
+                        out.extend_from_slice(&item.render());
+                    }
+                    Cow::Owned(out)
+                }
+                Value::Map(_) => Cow::Borrowed(b""),
+            }
+        }
 
+        /// Whether this value counts as "present" for `{% if %}`.
+        pub fn is_truthy(&self) -> bool {
+            match self {
+                Value::Str(s) => !s.is_empty(),
+                Value::Num(n) => *n != 0.0,
+                Value::Bool(b) => *b,
+                Value::List(items) => !items.is_empty(),
+                Value::Map(m) => !m.is_empty(),
+            }
+        }
+    }
+
+    /// What to do when a `{{ path }}` expression names a key that isn't
+    /// bound in the [`Context`].
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum MissingKeyPolicy {
+        /// Render nothing for the missing key.
+        #[default]
+        Empty,
+        /// Fail the whole `instantiate` call.
+        Error,
+    }
+
+    /// The variables a template is rendered against, keyed by the
+    /// top-level name used in `{{ name }}` / `{{ name.field }}`.
+    #[derive(Clone, Debug, Default)]
+    pub struct Context {
+        values: HashMap<Vec<u8>, Value>,
+        pub missing_key_policy: MissingKeyPolicy,
+    }
+
+    impl Context {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: Value) -> &mut Self {
+            self.values.insert(key.into(), value);
+            self
+        }
+
+        /// Resolve a dotted path such as `user.name` against this context,
+        /// descending through [`Value::Map`]s one segment at a time.
+        pub fn resolve(&self, path: &[u8]) -> Option<&Value> {
+            let mut segments = path.split(|&b| b == b'.');
+            let mut value = self.values.get(segments.next()?)?;
+            for segment in segments {
+                match value {
+                    Value::Map(m) => value = m.get(segment)?,
+                    _ => return None,
+                }
+            }
+            Some(value)
+        }
+    }
+}
+
+pub mod node {
+    //! Turns the flat [`NumberedBlock`] stream from [`parse_template`] into
+    //! a tree that pairs opening statements (`for`, `if`) with their
+    //! closing tag, the way orgize's `RawBlock` pairs `#+BEGIN_<name>`
+    //! with `#+END_<name>`.
+
+    use crate::{Block, NumberedBlock, Special};
+
+    /// One node of a parsed template, after block statements have been
+    /// matched up with their `{% end... %}`.
+    #[derive(Clone, Debug)]
+    pub enum Node<'a> {
+        Plain(&'a [u8]),
+        Expr(&'a [u8]),
+        Comment(&'a [u8]),
+        /// A `{% ... %}` statement this tree builder doesn't special-case
+        /// (anything but `for`/`if`/`else`/`endfor`/`endif`), passed
+        /// through to [`crate::handler::Handler::statement`] as-is.
+        Statement(&'a [u8]),
+        For {
+            var: &'a [u8],
+            iterable: &'a [u8],
+            body: Vec<Node<'a>>,
+        },
+        If {
+            cond: &'a [u8],
+            then: Vec<Node<'a>>,
+            else_: Option<Vec<Node<'a>>>,
+        },
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum TreeErrorKind {
+        /// A `{% for ... %}` has no matching `{% endfor %}`.
+        UnbalancedFor,
+        /// A `{% if ... %}` has no matching `{% endif %}`.
+        UnbalancedIf,
+        /// `{% for %}` wasn't of the form `for <var> in <iterable>`.
+        MalformedFor,
+        /// A stray `{% else %}`, `{% endfor %}` or `{% endif %}` with no
+        /// opener.
+        UnexpectedCloser,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct TreeError {
+        pub line_number: usize,
+        pub kind: TreeErrorKind,
+    }
+
+    fn split_word(s: &[u8]) -> (&[u8], &[u8]) {
+        let s = s.trim_ascii_start();
+        match s.iter().position(|b| b.is_ascii_whitespace()) {
+            Some(i) => (&s[..i], s[i..].trim_ascii_start()),
+            None => (s, b""),
+        }
+    }
+
+    /// Parse `<var> in <iterable>` out of a `for` statement's body.
+    fn parse_for_header(stmt: &[u8]) -> Option<(&[u8], &[u8])> {
+        let (var, rest) = split_word(stmt);
+        let (keyword, iterable) = split_word(rest);
+        if var.is_empty() || keyword != b"in" || iterable.is_empty() {
+            return None;
+        }
+        Some((var, iterable))
+    }
+
+    /// Parse the nodes starting at `*pos`, stopping (without consuming)
+    /// at the first `else`/`endfor`/`endif`, or at the end of `blocks`.
+    fn parse_nodes<'a>(
+        blocks: &[NumberedBlock<'a>],
+        pos: &mut usize,
+    ) -> Result<Vec<Node<'a>>, TreeError> {
+        let mut nodes = Vec::new();
+        while let Some(nb) = blocks.get(*pos) {
+            match nb.block {
+                Block::Plain(p) => {
+                    nodes.push(Node::Plain(p));
+                    *pos += 1;
+                }
+                Block::Special(Special::TagCurly(e)) => {
+                    nodes.push(Node::Expr(e));
+                    *pos += 1;
+                }
+                Block::Special(Special::TagHash(c)) => {
+                    nodes.push(Node::Comment(c));
+                    *pos += 1;
+                }
+                Block::Special(Special::TagPercent(stmt)) => {
+                    let (keyword, _) = split_word(stmt);
+                    match keyword {
+                        b"else" | b"endfor" | b"endif" => return Ok(nodes),
+                        b"for" => {
+                            let (var, iterable) = parse_for_header(split_word(stmt).1).ok_or(
+                                TreeError {
+                                    line_number: nb.line_number,
+                                    kind: TreeErrorKind::MalformedFor,
+                                },
+                            )?;
+                            *pos += 1;
+                            let body = parse_nodes(blocks, pos)?;
+                            expect_closer(blocks, pos, b"endfor", nb.line_number, TreeErrorKind::UnbalancedFor)?;
+                            nodes.push(Node::For { var, iterable, body });
+                        }
+                        b"if" => {
+                            let cond = split_word(stmt).1;
+                            *pos += 1;
+                            let then = parse_nodes(blocks, pos)?;
+                            let else_ = if peek_keyword(blocks, *pos) == Some(b"else") {
+                                *pos += 1;
+                                Some(parse_nodes(blocks, pos)?)
+                            } else {
+                                None
+                            };
+                            expect_closer(blocks, pos, b"endif", nb.line_number, TreeErrorKind::UnbalancedIf)?;
+                            nodes.push(Node::If { cond, then, else_ });
+                        }
+                        _ => {
+                            nodes.push(Node::Statement(stmt));
+                            *pos += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn peek_keyword<'a>(blocks: &[NumberedBlock<'a>], pos: usize) -> Option<&'a [u8]> {
+        match blocks.get(pos)?.block {
+            Block::Special(Special::TagPercent(stmt)) => Some(split_word(stmt).0),
+            _ => None,
+        }
+    }
+
+    fn expect_closer(
+        blocks: &[NumberedBlock<'_>],
+        pos: &mut usize,
+        expected: &[u8],
+        opener_line: usize,
+        kind: TreeErrorKind,
+    ) -> Result<(), TreeError> {
+        if peek_keyword(blocks, *pos) == Some(expected) {
+            *pos += 1;
+            Ok(())
+        } else {
+            Err(TreeError {
+                line_number: opener_line,
+                kind,
+            })
+        }
+    }
+
+    /// Build the node tree for a complete block stream, erroring if any
+    /// `for`/`if` is unbalanced or any closer is stray.
+    pub fn build_tree<'a>(blocks: &[NumberedBlock<'a>]) -> Result<Vec<Node<'a>>, TreeError> {
+        let mut pos = 0;
+        let nodes = parse_nodes(blocks, &mut pos)?;
+        if pos != blocks.len() {
+            let line_number = blocks[pos].line_number;
+            return Err(TreeError {
+                line_number,
+                kind: TreeErrorKind::UnexpectedCloser,
+            });
+        }
+        Ok(nodes)
+    }
+}
+
+pub mod error {
+    //! Rich parse errors: where they are, and what the offending line
+    //! looks like, instead of a bare `None`.
+
+    use crate::node::{TreeError, TreeErrorKind};
+    use crate::parse::{NumberedInput, Syntax};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ErrorKind {
+        /// A `{{`, `{%` or `{#` was opened but never closed.
+        UnterminatedTag,
+        /// A `{% for %}` has no matching `{% endfor %}`.
+        UnbalancedFor,
+        /// A `{% if %}` has no matching `{% endif %}`.
+        UnbalancedIf,
+        /// `{% for %}` wasn't of the form `for <var> in <iterable>`.
+        MalformedFor,
+        /// A stray `{% else %}`/`{% endfor %}`/`{% endif %}` with no opener.
+        UnexpectedCloser,
+        /// Parsing stopped before consuming the whole input, but not
+        /// because a tag was left open (e.g. a stray `}}` with no opener).
+        UnexpectedInput,
+    }
+
+    /// A template failed to parse, with the line, column, and a rendered
+    /// snippet of the offending source so callers can report it back to
+    /// whoever is authoring the template.
+    #[derive(Debug)]
+    pub struct TemplateError<'a> {
+        pub line: usize,
+        /// `None` when the error was only ever tracked by line (e.g.
+        /// unbalanced `for`/`if`), not by exact byte offset.
+        pub column: Option<usize>,
+        pub kind: ErrorKind,
+        line_text: &'a [u8],
+    }
+
+    impl<'a> TemplateError<'a> {
+        /// `syntax` is the one the template was parsed with, so we can tell
+        /// an actually-unterminated tag (parsing stopped right at one of
+        /// its *opening* delimiters) apart from trailing input that never
+        /// opened a tag at all (e.g. a stray, unopened `}}`).
+        pub(crate) fn from_parse_failure(
+            source: &'a [u8],
+            failing: NumberedInput<'a>,
+            syntax: &Syntax,
+        ) -> Self {
+            let offset = source.len() - failing.i.len();
+            let (column, line_text) = line_context(source, offset);
+            let kind = if syntax.opens_with(failing.i) {
+                ErrorKind::UnterminatedTag
+            } else {
+                ErrorKind::UnexpectedInput
+            };
+            Self {
+                line: failing.line_number,
+                column: Some(column),
+                kind,
+                line_text,
+            }
+        }
+
+        pub(crate) fn from_tree_error(source: &'a [u8], e: TreeError) -> Self {
+            let kind = match e.kind {
+                TreeErrorKind::UnbalancedFor => ErrorKind::UnbalancedFor,
+                TreeErrorKind::UnbalancedIf => ErrorKind::UnbalancedIf,
+                TreeErrorKind::MalformedFor => ErrorKind::MalformedFor,
+                TreeErrorKind::UnexpectedCloser => ErrorKind::UnexpectedCloser,
+            };
+            Self {
+                line: e.line_number,
+                column: None,
+                kind,
+                line_text: nth_line(source, e.line_number),
+            }
+        }
+
+        /// The full text of the offending line.
+        pub fn line_text(&self) -> &'a [u8] {
+            self.line_text
+        }
+
+        fn describe(&self) -> &'static str {
+            match self.kind {
+                ErrorKind::UnterminatedTag => "unterminated tag",
+                ErrorKind::UnbalancedFor => "unbalanced {% for %}: missing {% endfor %}",
+                ErrorKind::UnbalancedIf => "unbalanced {% if %}: missing {% endif %}",
+                ErrorKind::MalformedFor => {
+                    "malformed {% for %}: expected `for <var> in <iterable>`"
+                }
+                ErrorKind::UnexpectedCloser => {
+                    "{% else %}/{% endfor %}/{% endif %} with no matching opening tag"
+                }
+                ErrorKind::UnexpectedInput => "unexpected input with no tag to open it",
+            }
+        }
+    }
+
+    fn nth_line(source: &[u8], line_number: usize) -> &[u8] {
+        source.split(|&b| b == b'\n').nth(line_number).unwrap_or(b"")
+    }
+
+    /// The column (0-based, from the start of its line) and full text of
+    /// the line containing `offset`.
+    fn line_context(source: &[u8], offset: usize) -> (usize, &[u8]) {
+        let start = source[..offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map(|p| p + 1)
+            .unwrap_or(0);
+        let end = source[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|p| offset + p)
+            .unwrap_or(source.len());
+        (offset - start, &source[start..end])
+    }
+
+    impl<'a> std::fmt::Display for TemplateError<'a> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            writeln!(f, "{} (line {})", self.describe(), self.line + 1)?;
+            writeln!(f, "{}", String::from_utf8_lossy(self.line_text))?;
+            if let Some(column) = self.column {
+                writeln!(f, "{}^", " ".repeat(column))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl<'a> std::error::Error for TemplateError<'a> {}
+}
+
+use context::{Context, MissingKeyPolicy, Value};
+use error::TemplateError;
+use handler::Handler;
+use node::{build_tree, Node};
+
+/// Why [`ParsedTemplate::instantiate`] failed.
+#[derive(Debug)]
+pub enum InstantiateError {
+    Io(std::io::Error),
+    /// A `{{ path }}`/`{% if %}`/`{% for %}` expression didn't resolve (or a
+    /// `{% for %}` iterable resolved to something other than a list) and the
+    /// context's [`MissingKeyPolicy`] is [`MissingKeyPolicy::Error`].
+    MissingKey(Vec<u8>),
+}
+
+impl std::fmt::Display for InstantiateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstantiateError::Io(e) => write!(f, "{e}"),
+            InstantiateError::MissingKey(path) => {
+                write!(f, "missing key: {}", String::from_utf8_lossy(path))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstantiateError {}
+
+impl From<std::io::Error> for InstantiateError {
+    fn from(e: std::io::Error) -> Self {
+        InstantiateError::Io(e)
+    }
+}
+
+#[derive(Debug)]
 pub struct ParsedTemplate<'a> {
-    parsed: Vec<NumberedBlock<'a>>,
+    nodes: Vec<Node<'a>>,
 }
 
 impl<'a> ParsedTemplate<'a> {
-    pub fn new(template: &'a [u8]) -> Option<Self> {
-        parse_template(&template)
-            .ok()
-            .filter(|x| x.0.i.len() == 0)
-            .map(|(_, parsed)| Self { parsed })
-    }
-
-    pub fn instantiate(&self, wr: &mut impl Write) -> Result<(), std::io::Error> {
-        for ins in self.parsed.iter() {
-            match ins.block {
-                Block::Plain(x) => wr.write_all(x)?,
-                Block::Special(s) => match s {
-                    parse::Special::TagPercent(s) => wr.write_all(s)?,
-                    parse::Special::TagCurly(s) => wr.write_all(s)?,
-                    parse::Special::TagHash(s) => wr.write_all(s)?,
-                },
+    /// Parse using the engine's built-in `{% %}`/`{{ }}`/`{# #}` delimiters.
+    pub fn new(template: &'a [u8]) -> Result<Self, TemplateError<'a>> {
+        Self::new_with(template, &Syntax::default())
+    }
+
+    /// Parse using a custom tag [`Syntax`].
+    pub fn new_with(template: &'a [u8], syntax: &Syntax) -> Result<Self, TemplateError<'a>> {
+        let (rest, blocks) = match parse_template_with(template, syntax) {
+            Ok(ok) => ok,
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                return Err(TemplateError::from_parse_failure(template, e.input, syntax));
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                unreachable!("parse_template_with is built from nom::bytes::complete parsers")
+            }
+        };
+        if !rest.i.is_empty() {
+            return Err(TemplateError::from_parse_failure(template, rest, syntax));
+        }
+        let nodes =
+            build_tree(&blocks).map_err(|e| TemplateError::from_tree_error(template, e))?;
+        Ok(Self { nodes })
+    }
+
+    pub fn instantiate(
+        &self,
+        ctx: &Context,
+        handler: &mut impl Handler,
+        wr: &mut impl Write,
+    ) -> Result<(), InstantiateError> {
+        instantiate_nodes(&self.nodes, &mut Scope::new(ctx), handler, wr)
+    }
+}
+
+/// A [`Context`] plus a stack of loop-variable bindings that shadow it.
+///
+/// `{% for %}` pushes one binding per nesting level onto `shadow` instead
+/// of cloning the whole [`Context`], so a loop's cost no longer scales
+/// with how much else is bound in scope.
+struct Scope<'a> {
+    ctx: &'a Context,
+    shadow: Vec<(Vec<u8>, Value)>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(ctx: &'a Context) -> Self {
+        Self {
+            ctx,
+            shadow: Vec::new(),
+        }
+    }
+
+    fn missing_key_policy(&self) -> MissingKeyPolicy {
+        self.ctx.missing_key_policy
+    }
+
+    fn push(&mut self, var: Vec<u8>, value: Value) {
+        self.shadow.push((var, value));
+    }
+
+    fn pop(&mut self) {
+        self.shadow.pop();
+    }
+
+    /// Resolve a dotted path, checking the shadow stack (most recently
+    /// pushed binding first) before falling back to the underlying
+    /// [`Context`].
+    fn resolve(&self, path: &[u8]) -> Option<&Value> {
+        let mut segments = path.split(|&b| b == b'.');
+        let key = segments.next()?;
+        let mut value = match self.shadow.iter().rev().find(|(k, _)| k.as_slice() == key) {
+            Some((_, value)) => value,
+            None => return self.ctx.resolve(path),
+        };
+        for segment in segments {
+            match value {
+                Value::Map(m) => value = m.get(segment)?,
+                _ => return None,
             }
         }
-        Ok(())
+        Some(value)
     }
 }
 
+fn instantiate_nodes(
+    nodes: &[Node<'_>],
+    scope: &mut Scope<'_>,
+    handler: &mut impl Handler,
+    wr: &mut impl Write,
+) -> Result<(), InstantiateError> {
+    for node in nodes {
+        match node {
+            Node::Plain(p) => wr.write_all(p)?,
+            Node::Statement(s) => handler.statement(s, wr)?,
+            Node::Comment(c) => handler.comment(c, wr)?,
+            Node::Expr(path) => match scope.resolve(path.trim_ascii()) {
+                Some(value) => handler.expr(&value.render(), wr)?,
+                None if scope.missing_key_policy() == MissingKeyPolicy::Empty => {}
+                None => return Err(InstantiateError::MissingKey(path.to_vec())),
+            },
+            Node::If { cond, then, else_ } => {
+                let truthy = match scope.resolve(cond.trim_ascii()) {
+                    Some(value) => value.is_truthy(),
+                    None if scope.missing_key_policy() == MissingKeyPolicy::Empty => false,
+                    None => return Err(InstantiateError::MissingKey(cond.to_vec())),
+                };
+                if truthy {
+                    instantiate_nodes(then, scope, handler, wr)?;
+                } else if let Some(else_) = else_ {
+                    instantiate_nodes(else_, scope, handler, wr)?;
+                }
+            }
+            Node::For {
+                var,
+                iterable,
+                body,
+            } => match scope.resolve(iterable.trim_ascii()) {
+                Some(Value::List(items)) => {
+                    let items = items.clone();
+                    let var = var.trim_ascii().to_vec();
+                    for item in items {
+                        scope.push(var.clone(), item);
+                        let result = instantiate_nodes(body, scope, handler, wr);
+                        scope.pop();
+                        result?;
+                    }
+                }
+                Some(_) | None if scope.missing_key_policy() == MissingKeyPolicy::Empty => {}
+                Some(_) | None => return Err(InstantiateError::MissingKey(iterable.to_vec())),
+            },
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use core::str;
     use std::io::Cursor;
 
     use super::*;
+    use context::Value;
+    use handler::{EchoHandler, EscapeHtmlHandler};
 
     #[test]
     fn it_works() {
         let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("world", Value::Str(b"world".to_vec()));
         let t = ParsedTemplate::new(b"Hello {{ world }}").unwrap();
-        t.instantiate(&mut c).unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
         assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "Hello world");
     }
+
+    #[test]
+    fn resolves_dotted_path() {
+        let mut c = Cursor::new(Vec::new());
+        let mut user = std::collections::HashMap::new();
+        user.insert(b"name".to_vec(), Value::Str(b"Ada".to_vec()));
+        let mut ctx = Context::new();
+        ctx.insert("user", Value::Map(user));
+        let t = ParsedTemplate::new(b"Hello {{ user.name }}").unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "Hello Ada");
+    }
+
+    #[test]
+    fn missing_key_defaults_to_empty() {
+        let mut c = Cursor::new(Vec::new());
+        let ctx = Context::new();
+        let t = ParsedTemplate::new(b"Hello {{ missing }}!").unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "Hello !");
+    }
+
+    #[test]
+    fn missing_key_can_error() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.missing_key_policy = context::MissingKeyPolicy::Error;
+        let t = ParsedTemplate::new(b"Hello {{ missing }}!").unwrap();
+        assert!(matches!(
+            t.instantiate(&ctx, &mut EchoHandler, &mut c),
+            Err(InstantiateError::MissingKey(_))
+        ));
+    }
+
+    #[test]
+    fn if_condition_can_error_on_missing_key() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.missing_key_policy = context::MissingKeyPolicy::Error;
+        let t = ParsedTemplate::new(b"{% if missing %}yes{% endif %}").unwrap();
+        assert!(matches!(
+            t.instantiate(&ctx, &mut EchoHandler, &mut c),
+            Err(InstantiateError::MissingKey(_))
+        ));
+    }
+
+    #[test]
+    fn renders_for_loop_over_a_list() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert(
+            "items",
+            Value::List(vec![
+                Value::Str(b"a".to_vec()),
+                Value::Str(b"b".to_vec()),
+                Value::Str(b"c".to_vec()),
+            ]),
+        );
+        let t = ParsedTemplate::new(b"{% for item in items %}({{ item }}){% endfor %}").unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "(a)(b)(c)");
+    }
+
+    #[test]
+    fn renders_if_else_branches() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("flag", Value::Bool(true));
+        let t = ParsedTemplate::new(b"{% if flag %}yes{% else %}no{% endif %}").unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "yes");
+
+        let mut c = Cursor::new(Vec::new());
+        ctx.insert("flag", Value::Bool(false));
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "no");
+    }
+
+    #[test]
+    fn renders_if_nested_inside_for() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert(
+            "items",
+            Value::List(vec![Value::Bool(true), Value::Bool(false), Value::Bool(true)]),
+        );
+        let t = ParsedTemplate::new(
+            b"{% for item in items %}{% if item %}y{% else %}n{% endif %}{% endfor %}",
+        )
+        .unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "yny");
+    }
+
+    #[test]
+    fn renders_for_nested_inside_if() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("flag", Value::Bool(true));
+        ctx.insert(
+            "items",
+            Value::List(vec![
+                Value::Str(b"a".to_vec()),
+                Value::Str(b"b".to_vec()),
+            ]),
+        );
+        let t = ParsedTemplate::new(
+            b"{% if flag %}{% for item in items %}({{ item }}){% endfor %}{% else %}none{% endif %}",
+        )
+        .unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "(a)(b)");
+    }
+
+    #[test]
+    fn renders_for_nested_inside_for() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert(
+            "outer",
+            Value::List(vec![
+                Value::List(vec![Value::Str(b"a".to_vec()), Value::Str(b"b".to_vec())]),
+                Value::List(vec![Value::Str(b"c".to_vec())]),
+            ]),
+        );
+        let t = ParsedTemplate::new(
+            b"{% for inner in outer %}[{% for item in inner %}{{ item }}{% endfor %}]{% endfor %}",
+        )
+        .unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "[ab][c]");
+    }
+
+    #[test]
+    fn for_loop_var_shadows_and_restores_outer_binding() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("item", Value::Str(b"outer".to_vec()));
+        ctx.insert(
+            "items",
+            Value::List(vec![Value::Str(b"inner".to_vec())]),
+        );
+        let t = ParsedTemplate::new(
+            b"{{ item }}-{% for item in items %}{{ item }}{% endfor %}-{{ item }}",
+        )
+        .unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "outer-inner-outer");
+    }
+
+    #[test]
+    fn unbalanced_for_fails_to_parse() {
+        let err = ParsedTemplate::new(b"{% for item in items %}{{ item }}").unwrap_err();
+        assert_eq!(err.kind, error::ErrorKind::UnbalancedFor);
+    }
+
+    #[test]
+    fn unterminated_tag_points_at_the_opening_line() {
+        let err = ParsedTemplate::new(b"one\ntwo {{ oops\nthree").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert_eq!(err.kind, error::ErrorKind::UnterminatedTag);
+        assert_eq!(err.line_text(), b"two {{ oops");
+    }
+
+    #[test]
+    fn stray_closer_reports_unexpected_input_not_unterminated_tag() {
+        let err = ParsedTemplate::new(b"abc}}").unwrap_err();
+        assert_eq!(err.kind, error::ErrorKind::UnexpectedInput);
+        assert_eq!(err.line_text(), b"abc}}");
+    }
+
+    #[test]
+    fn renders_with_a_custom_syntax() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::Str(b"Ada".to_vec()));
+        let syntax = parse::Syntax {
+            curly: (b"<%", b"%>"),
+            ..Default::default()
+        };
+        let t = ParsedTemplate::new_with(b"Hi <% name %>!", &syntax).unwrap();
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "Hi Ada!");
+
+        // the default syntax's own `{{` is now just plain text
+        let t = ParsedTemplate::new_with(b"literal {{ braces }}", &syntax).unwrap();
+        let mut c = Cursor::new(Vec::new());
+        t.instantiate(&ctx, &mut EchoHandler, &mut c).unwrap();
+        assert_eq!(str::from_utf8(c.get_ref()).unwrap(), "literal {{ braces }}");
+    }
+
+    #[test]
+    fn escapes_html_in_expr_output() {
+        let mut c = Cursor::new(Vec::new());
+        let mut ctx = Context::new();
+        ctx.insert("name", Value::Str(b"<b>hi</b> & 'quotes'".to_vec()));
+        let t = ParsedTemplate::new(b"{{ name }}").unwrap();
+        t.instantiate(&ctx, &mut EscapeHtmlHandler, &mut c).unwrap();
+        assert_eq!(
+            str::from_utf8(c.get_ref()).unwrap(),
+            "&lt;b&gt;hi&lt;/b&gt; &amp; &#39;quotes&#39;"
+        );
+    }
 }